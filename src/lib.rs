@@ -0,0 +1,750 @@
+extern crate failure;
+extern crate globset;
+extern crate log;
+extern crate notify;
+extern crate percent_encoding;
+
+use failure::{bail, Error};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::debug;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+pub use notify::Event as FsEvent;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, canonicalize, File};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Result<R> = std::result::Result<R, Error>;
+
+// How often `Monitor::run` wakes up to check for new stdin input while
+// waiting on FS events, so input commands are never delayed by more than
+// this even if the debounce window is long.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long the FS event stream must stay quiet before we flush a CHANGES
+// notification for the replicas that got dirty. This coalesces bursts of
+// raw events (e.g. a large checkout or `rsync`) into a single notification.
+const DEFAULT_DEBOUNCE_MS: u64 = 250;
+
+pub fn debounce_window() -> Duration {
+    let millis = env::var("UNISON_FSMONITOR_DEBOUNCE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_DEBOUNCE_MS);
+    Duration::from_millis(millis)
+}
+
+// Above this many changed paths under one ancestor directory, we collapse
+// them into a single `RECURSIVE <dir>` for that ancestor instead of sending
+// one line per path. Unison rescans the directory either way, so this is
+// safe; it just keeps the CHANGES reply from ballooning after e.g. a large
+// checkout rewrites a whole subtree.
+const DEFAULT_COALESCE_THRESHOLD: usize = 128;
+
+pub fn coalesce_threshold() -> usize {
+    env::var("UNISON_FSMONITOR_COALESCE_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_COALESCE_THRESHOLD)
+}
+
+// A trie over the components of the changed relative paths for one replica,
+// used to find the common ancestor directories to fold bursts of sibling
+// changes up to. Never collapses past the replica root (the trie root).
+#[derive(Default)]
+struct ChangeTrie {
+    leaf: bool,
+    children: BTreeMap<OsString, ChangeTrie>,
+}
+
+impl ChangeTrie {
+    fn insert(&mut self, path: &Path) {
+        let mut node = self;
+        for component in path.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_owned())
+                .or_default();
+        }
+        node.leaf = true;
+    }
+
+    fn count(&self) -> usize {
+        self.leaf as usize + self.children.values().map(ChangeTrie::count).sum::<usize>()
+    }
+
+    // Returns the folded entries for this subtree, collapsing any child
+    // whose own descendant count crosses `threshold` into one entry for
+    // that child.
+    fn fold(&self, prefix: &Path, threshold: usize) -> HashSet<PathBuf> {
+        let mut entries = HashSet::new();
+        if self.leaf {
+            entries.insert(prefix.to_owned());
+        }
+
+        for (name, child) in &self.children {
+            let child_prefix = prefix.join(name);
+            if child.count() > threshold {
+                entries.insert(child_prefix);
+            } else {
+                entries.extend(child.fold(&child_prefix, threshold));
+            }
+        }
+
+        entries
+    }
+}
+
+// Fold `paths` (relative to a replica root) so that any directory whose
+// descendant count crosses `threshold` is replaced by a single entry for
+// that directory. If the resulting entries still outnumber `threshold` --
+// e.g. a large checkout that touches one file in each of a thousand leaf
+// directories, where no single subdirectory is individually over threshold
+// -- the whole replica folds to one `RESCAN_ROOT` entry instead.
+fn coalesce_changes(paths: HashSet<PathBuf>, threshold: usize) -> HashSet<PathBuf> {
+    if paths.len() <= threshold {
+        return paths;
+    }
+
+    let mut trie = ChangeTrie::default();
+    for path in &paths {
+        trie.insert(path);
+    }
+
+    let entries = trie.fold(Path::new(""), threshold);
+    if entries.len() > threshold {
+        return [PathBuf::from(RESCAN_ROOT)].into_iter().collect();
+    }
+    entries
+}
+
+// Mirrors rust-analyzer's `ChangeKind`: what kind of change a path saw,
+// kept internally so renames and deletions can be handled precisely even
+// though the wire protocol only ever reports `RECURSIVE <path>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeKind {
+    Create,
+    Write,
+    Remove,
+}
+
+// Classify a typed notify event into the individual paths it touched, along
+// with what happened to each. Renames report both the old path (as a
+// `Remove`) and the new path (as a `Create`), so an atomic-rename save
+// (common with editors) marks both sides dirty instead of one ambiguous event.
+// `RenameMode::Any` (FSEvents, kqueue) reports each side as its own
+// single-path event with no label for which side it is, so we tell them
+// apart by whether the path still exists on disk.
+fn classify(event: &FsEvent) -> Vec<(PathBuf, ChangeKind)> {
+    match event.kind {
+        EventKind::Create(_) => event
+            .paths
+            .iter()
+            .map(|p| (p.clone(), ChangeKind::Create))
+            .collect(),
+        EventKind::Remove(_) => event
+            .paths
+            .iter()
+            .map(|p| (p.clone(), ChangeKind::Remove))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut changes = vec![];
+            if let Some(from) = event.paths.first() {
+                changes.push((from.clone(), ChangeKind::Remove));
+            }
+            if let Some(to) = event.paths.get(1) {
+                changes.push((to.clone(), ChangeKind::Create));
+            }
+            changes
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => event
+            .paths
+            .iter()
+            .map(|p| (p.clone(), ChangeKind::Remove))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event
+            .paths
+            .iter()
+            .map(|p| (p.clone(), ChangeKind::Create))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => event
+            .paths
+            .iter()
+            .map(|p| {
+                let kind = if p.exists() {
+                    ChangeKind::Create
+                } else {
+                    ChangeKind::Remove
+                };
+                (p.clone(), kind)
+            })
+            .collect(),
+        _ => event
+            .paths
+            .iter()
+            .map(|p| (p.clone(), ChangeKind::Write))
+            .collect(),
+    }
+}
+
+// Where we persist which replicas we've already watched across restarts, so
+// a respawned monitor can tell it may have missed changes instead of
+// silently carrying on as if nothing happened while it was down.
+//
+// This is deliberately just a set of canonical replica paths, not a log of
+// event ids: we don't do FSEvents `since_when` history replay (see the
+// comment on `START` below), so there's nothing to replay *to* and no id to
+// track. All a restart needs to know is "have we watched this path before",
+// which is exactly what set membership gives us.
+//
+// TODO(chunk0-5): this means every restart forces a full rescan on every
+// platform, not just the non-macOS ones the request expected to fall back
+// to it. Real replay needs to bypass notify's cross-platform `Watcher` and
+// talk to the macOS-only FSEventStream API directly -- unsafe,
+// platform-specific surface that isn't wired up here. A rescan is always
+// correct, just more expensive; whether that's an acceptable permanent
+// trade-off or worth building replay for is a call for whoever owns this
+// request, not something to quietly settle in a comment.
+pub fn state_file_path() -> PathBuf {
+    env::var("UNISON_FSMONITOR_STATE_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            let home = env::var("HOME").map(PathBuf::from).unwrap_or_else(|_| env::temp_dir());
+            home.join(".unison-fsmonitor").join("event-state")
+        })
+}
+
+// One percent-encoded canonical replica path per line.
+fn load_seen_replicas(path: &Path) -> Result<HashSet<PathBuf>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(contents
+        .lines()
+        .map(|line| PathBuf::from(decode(line).as_ref()))
+        .collect())
+}
+
+fn save_seen_replicas(path: &Path, seen: &HashSet<PathBuf>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    for replica_path in seen {
+        writeln!(file, "{}", encode(&replica_path.to_string_lossy()).as_ref())?;
+    }
+    Ok(())
+}
+
+fn encode(s: &str) -> impl AsRef<str> {
+    percent_encoding::utf8_percent_encode(s, percent_encoding::SIMPLE_ENCODE_SET).to_string()
+}
+
+fn decode<'a>(s: &'a str) -> impl AsRef<str> + 'a {
+    percent_encoding::percent_decode(s.as_bytes()).decode_utf8_lossy()
+}
+
+// Expand a raw `IGNORE` pattern into the globs that give it gitignore's
+// directory semantics: a pattern with no `/` matches the name at any depth
+// rather than only at the replica root. We register the anchored pattern
+// both on its own (so file patterns like `*.log` match the file itself) and
+// suffixed with `/**` (so a directory pattern like `node_modules` also
+// matches everything below it -- gitignore never reports changes inside an
+// ignored directory).
+fn expand_ignore_pattern(pattern: &str) -> [String; 2] {
+    // A trailing slash is gitignore's directory-only marker (`node_modules/`);
+    // strip it so that case is anchored the same way as the bare name.
+    let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+
+    let anchored = if pattern.contains('/') {
+        pattern.to_owned()
+    } else {
+        format!("**/{}", pattern)
+    };
+
+    let contents = format!("{}/**", anchored);
+    [anchored, contents]
+}
+
+fn send_cmd(out: &mut impl Write, cmd: &str, args: &[&str]) -> Result<()> {
+    let mut output = cmd.to_owned();
+    for arg in args {
+        output += " ";
+        output += encode(arg).as_ref();
+    }
+
+    debug!(">> {}", output);
+    writeln!(out, "{}", output)?;
+    Ok(())
+}
+
+fn send_ack(out: &mut impl Write) -> Result<()> {
+    send_cmd(out, "OK", &[])
+}
+
+fn send_changes(out: &mut impl Write, replica: &str) -> Result<()> {
+    send_cmd(out, "CHANGES", &[replica])
+}
+
+fn send_recursive(out: &mut impl Write, path: &str) -> Result<()> {
+    send_cmd(out, "RECURSIVE", &[path])
+}
+
+// Sentinel relative path standing for "rescan the whole replica", used when
+// a restart forces a full rescan (see `START` below) in place of a normal
+// per-path change. It must render as a real wire token: an empty path would
+// serialize to a bare `RECURSIVE` with no argument, which Unison's
+// whitespace-split parser drops, silently losing the rescan.
+const RESCAN_ROOT: &str = ".";
+
+fn send_done(out: &mut impl Write) -> Result<()> {
+    send_cmd(out, "DONE", &[])
+}
+
+// Tells the peer about the error and then fails the command, so the error
+// propagates out of `Monitor::run` instead of killing the process directly
+// (which would make this impossible to exercise from a test).
+fn send_error(out: &mut impl Write, msg: &str) -> Result<()> {
+    send_cmd(out, "ERROR", &[msg])?;
+    bail!("{}", msg.to_owned())
+}
+
+fn parse_input(input: &str) -> Result<(String, Vec<String>)> {
+    let mut cmd = String::new();
+    let mut args = vec![];
+    for (idx, word) in input.split_whitespace().enumerate() {
+        if idx == 0 {
+            cmd = word.to_owned();
+        } else {
+            args.push(decode(word).as_ref().to_owned())
+        }
+    }
+    Ok((cmd, args))
+}
+
+// Abstracts over the filesystem watcher so the protocol state machine below
+// can be driven by a real `notify` watcher or, in tests, by `FakeEventSource`
+// — following Zed's `Fs` trait + `FakeFs` split.
+pub trait EventSource {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<()>;
+    fn unwatch(&mut self, path: &Path) -> Result<()>;
+    fn recv_timeout(&mut self, timeout: Duration) -> std::result::Result<FsEvent, RecvTimeoutError>;
+}
+
+pub struct NotifyEventSource {
+    watcher: RecommendedWatcher,
+    events: Receiver<FsEvent>,
+}
+
+impl NotifyEventSource {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = channel();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<FsEvent>| match res {
+            Ok(event) => {
+                let _ = tx.send(event);
+            }
+            Err(err) => {
+                debug!("watch error: {:?}", err);
+            }
+        })?;
+        Ok(NotifyEventSource {
+            watcher,
+            events: rx,
+        })
+    }
+}
+
+impl EventSource for NotifyEventSource {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<()> {
+        Ok(self.watcher.watch(path, mode)?)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        Ok(self.watcher.unwatch(path)?)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> std::result::Result<FsEvent, RecvTimeoutError> {
+        self.events.recv_timeout(timeout)
+    }
+}
+
+// An in-memory `EventSource` for integration tests: events are pushed by the
+// test rather than observed from the real filesystem, and delivery can be
+// paused so a test can set up several synthetic events before the monitor's
+// event loop is allowed to see any of them (mirrors Zed's `FakeFs`
+// `pause_events`/`flush_events`/`buffered_events`).
+#[derive(Default)]
+pub struct FakeEventSource {
+    pub watched: HashSet<PathBuf>,
+    paused: bool,
+    buffered: VecDeque<FsEvent>,
+}
+
+impl FakeEventSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_event(&mut self, event: FsEvent) {
+        self.buffered.push_back(event);
+    }
+
+    pub fn pause_events(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn flush_events(&mut self) {
+        self.paused = false;
+    }
+}
+
+impl EventSource for FakeEventSource {
+    fn watch(&mut self, path: &Path, _mode: RecursiveMode) -> Result<()> {
+        self.watched.insert(path.to_owned());
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.watched.remove(path);
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, _timeout: Duration) -> std::result::Result<FsEvent, RecvTimeoutError> {
+        if self.paused {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        self.buffered.pop_front().ok_or(RecvTimeoutError::Timeout)
+    }
+}
+
+// Holds the protocol state machine: replicas, pending changes, the link map,
+// ignore globs and the restart-recovery bookkeeping. `run` drives it from a
+// real stdin/stdout/`EventSource`; tests drive `handle_input`/`handle_fs_event`
+// directly against a `FakeEventSource`.
+pub struct Monitor {
+    replicas: HashMap<String, String>,
+    pending_changes: HashMap<String, HashMap<PathBuf, ChangeKind>>,
+    link_map: HashMap<PathBuf, HashSet<PathBuf>>,
+    ignore_globs: HashMap<String, Vec<Glob>>,
+    ignore_sets: HashMap<String, GlobSet>,
+    dirty_replicas: HashSet<String>,
+    last_activity: Option<Instant>,
+    replica_path: String,
+    seen_replicas: HashSet<PathBuf>,
+    state_path: Option<PathBuf>,
+    debounce: Duration,
+    coalesce_threshold: usize,
+}
+
+impl Monitor {
+    // `state_path` persists restart-recovery bookkeeping to disk; pass `None`
+    // to keep it in memory only (what tests want).
+    pub fn new(debounce: Duration, coalesce_threshold: usize, state_path: Option<PathBuf>) -> Result<Self> {
+        let seen_replicas = match &state_path {
+            Some(path) => load_seen_replicas(path)?,
+            None => HashSet::new(),
+        };
+
+        Ok(Monitor {
+            replicas: HashMap::new(),
+            pending_changes: HashMap::new(),
+            link_map: HashMap::new(),
+            ignore_globs: HashMap::new(),
+            ignore_sets: HashMap::new(),
+            dirty_replicas: HashSet::new(),
+            last_activity: None,
+            replica_path: String::new(),
+            seen_replicas,
+            state_path,
+            debounce,
+            coalesce_threshold,
+        })
+    }
+
+    pub fn pending_changes(&self, replica: &str) -> HashSet<PathBuf> {
+        self.pending_changes
+            .get(replica)
+            .map(|changes| changes.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn persist_seen_replicas(&self) -> Result<()> {
+        if let Some(path) = &self.state_path {
+            save_seen_replicas(path, &self.seen_replicas)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut impl Write) -> Result<()> {
+        for id in self.dirty_replicas.drain() {
+            send_changes(out, &id)?;
+        }
+        self.last_activity = None;
+        Ok(())
+    }
+
+    // Flushes dirty replicas once the debounce window has elapsed with no
+    // further FS activity, returning whether a flush happened. Exposed so
+    // the debounce behavior can be exercised directly in tests, without
+    // going through `run`'s background input thread.
+    pub fn flush_if_quiet(&mut self, out: &mut impl Write) -> Result<bool> {
+        let quiet = self
+            .last_activity
+            .is_some_and(|started| started.elapsed() >= self.debounce);
+        if quiet {
+            self.flush(out)?;
+        }
+        Ok(quiet)
+    }
+
+    pub fn handle_input(
+        &mut self,
+        input: &str,
+        source: &mut impl EventSource,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let (cmd, mut args) = parse_input(input)?;
+
+        match cmd.as_str() {
+            "VERSION" => {
+                let version = args.remove(0);
+                if version != "1" {
+                    bail!("Unexpected version: {:?}", version);
+                }
+            }
+            "START" => {
+                // Start observing replica.
+                let replica_id = args.remove(0);
+                self.replica_path = args.remove(0);
+
+                // TODO: is recursive necessary here?
+                source.watch(Path::new(&self.replica_path), RecursiveMode::Recursive)?;
+                self.replicas
+                    .insert(replica_id.clone(), self.replica_path.clone());
+                debug!("replicas: {:?}", self.replicas);
+
+                // We've watched this replica before (the monitor may have
+                // restarted since). Real FSEvents history replay
+                // (`since_when`) needs to bypass notify's cross-platform
+                // Watcher and talk to the FSEvents API directly, which isn't
+                // wired up here (see the TODO on `state_file_path` above);
+                // until it is, force a full rescan so changes made while we
+                // were down aren't silently missed.
+                let canonical = canonicalize(&self.replica_path)
+                    .unwrap_or_else(|_| PathBuf::from(&self.replica_path));
+                if !self.seen_replicas.insert(canonical) {
+                    self.pending_changes
+                        .entry(replica_id.clone())
+                        .or_default()
+                        .insert(RESCAN_ROOT.into(), ChangeKind::Write);
+                    self.dirty_replicas.insert(replica_id.clone());
+                    self.last_activity = Some(Instant::now());
+                }
+                self.persist_seen_replicas()?;
+
+                send_ack(out)?;
+            }
+            "LINK" => {
+                // Follow a link.
+                let filename = args.remove(0);
+                let link = PathBuf::from(&self.replica_path).join(filename);
+                let realpath = canonicalize(&link)?;
+
+                source.watch(&realpath, RecursiveMode::Recursive)?;
+                self.link_map.entry(realpath).or_default().insert(link);
+                send_ack(out)?;
+            }
+            "DIR" => {
+                send_ack(out)?;
+            }
+            "IGNORE" => {
+                // Add a gitignore-style glob for this replica; changes under
+                // the replica that match it are not reported. `globset` itself only
+                // matches the literal pattern given to it, so a bare
+                // directory name like `node_modules` would match the
+                // directory entry but not anything below it; expand it the
+                // way gitignore would, so matching a directory also matches
+                // its descendants. This isn't full gitignore (no negation,
+                // no `.gitignore`-file semantics) -- callers that need exact
+                // anchoring can still write out a pattern like `dir/**`.
+                let replica_id = args.remove(0);
+                let pattern = args.remove(0);
+
+                let globs = self.ignore_globs.entry(replica_id.clone()).or_default();
+                for expanded in expand_ignore_pattern(&pattern) {
+                    match Glob::new(&expanded) {
+                        Ok(glob) => globs.push(glob),
+                        Err(err) => {
+                            return send_error(out, &format!("Invalid ignore pattern {:?}: {}", pattern, err));
+                        }
+                    }
+                }
+
+                let mut builder = GlobSetBuilder::new();
+                for glob in globs.iter() {
+                    builder.add(glob.clone());
+                }
+                self.ignore_sets.insert(replica_id, builder.build()?);
+                send_ack(out)?;
+            }
+            "WAIT" => {
+                // Start waiting replica.
+                let replica = args.remove(0);
+                if !self.replicas.contains_key(&replica) {
+                    send_error(out, &format!("Unknown replica: {}", replica))?;
+                }
+            }
+            "CHANGES" => {
+                // Request pending changes.
+                let replica = args.remove(0);
+                let replica_changes = self.pending_changes.remove(&replica).unwrap_or_default();
+                let changed_paths: HashSet<PathBuf> = replica_changes.into_keys().collect();
+                let changed_paths = coalesce_changes(changed_paths, self.coalesce_threshold);
+                for c in changed_paths {
+                    send_recursive(out, c.to_string_lossy().as_ref())?;
+                }
+                debug!("pending_changes: {:?}", self.pending_changes);
+                send_done(out)?;
+            }
+            "RESET" => {
+                // Stop observing replica.
+                let replica = args.remove(0);
+                source.unwatch(Path::new(&replica))?;
+                if let Some(replica_path) = self.replicas.remove(&replica) {
+                    // Otherwise a RESET followed by a re-START of the same
+                    // path within one session would hit the "seen before"
+                    // branch of START and trigger a spurious full rescan.
+                    let canonical = canonicalize(&replica_path).unwrap_or_else(|_| PathBuf::from(&replica_path));
+                    self.seen_replicas.remove(&canonical);
+                    self.persist_seen_replicas()?;
+                }
+                debug!("replicas: {:?}", self.replicas);
+            }
+            "DEBUG" | "DONE" => {
+                // TODO: update debug level.
+            }
+            _ => {
+                send_error(out, &format!("Unexpected cmd: {}", cmd))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_fs_event(&mut self, fsevent: FsEvent, source: &mut impl EventSource) -> Result<()> {
+        debug!("FS event: {:?}", fsevent);
+
+        self.last_activity = Some(Instant::now());
+
+        let mut matched_replica_ids = HashSet::new();
+
+        for (file_path, kind) in classify(&fsevent) {
+            let mut paths = HashSet::new();
+            paths.insert(file_path.clone());
+            for (realpath, links) in &self.link_map {
+                if file_path.starts_with(realpath) {
+                    for link in links {
+                        paths.insert(PathBuf::from(link).join(file_path.strip_prefix(realpath)?));
+                    }
+                }
+            }
+
+            // A vanished watched subtree (a LINK target) no longer needs its
+            // own watch; drop it instead of leaking it. This happens after the
+            // translation above so the removal is still reported under each
+            // link path.
+            if kind == ChangeKind::Remove && self.link_map.contains_key(&file_path) {
+                let _ = source.unwatch(&file_path);
+                self.link_map.remove(&file_path);
+            }
+
+            for path in paths {
+                for (replica_id, replica_path) in &self.replicas {
+                    if path.starts_with(replica_path) {
+                        let relative_path = path.strip_prefix(replica_path)?;
+
+                        if let Some(ignore) = self.ignore_sets.get(replica_id) {
+                            if ignore.is_match(relative_path) {
+                                continue;
+                            }
+                        }
+
+                        matched_replica_ids.insert(replica_id.clone());
+                        self.pending_changes
+                            .entry(replica_id.clone())
+                            .or_default()
+                            .insert(relative_path.into(), kind);
+                        debug!("pending_changes: {:?}", self.pending_changes);
+                    }
+                }
+            }
+        }
+
+        self.dirty_replicas.extend(matched_replica_ids);
+        Ok(())
+    }
+
+    // Drives the protocol state machine from `input` (one command per line)
+    // and a filesystem `source` until either is disconnected, writing
+    // protocol replies to `out`. Input commands are handled promptly and are
+    // never delayed by the debounce timer.
+    pub fn run<R, W, S>(&mut self, input: R, mut out: W, mut source: S) -> Result<()>
+    where
+        R: BufRead + Send + 'static,
+        W: Write,
+        S: EventSource,
+    {
+        send_cmd(&mut out, "VERSION", &["1"])?;
+
+        let (input_tx, input_rx): (Sender<String>, Receiver<String>) = channel();
+        thread::spawn(move || -> Result<()> {
+            let mut input = input;
+            loop {
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                input_tx.send(line)?;
+            }
+        });
+
+        loop {
+            match input_rx.try_recv() {
+                Ok(line) => {
+                    debug!("<< {}", line.trim());
+                    self.handle_input(&line, &mut source, &mut out)?;
+                    continue;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+
+            let wait = match self.last_activity {
+                Some(started) => self
+                    .debounce
+                    .checked_sub(started.elapsed())
+                    .unwrap_or_default()
+                    .min(POLL_INTERVAL),
+                None => POLL_INTERVAL,
+            };
+
+            match source.recv_timeout(wait) {
+                Ok(event) => self.handle_fs_event(event, &mut source)?,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.flush_if_quiet(&mut out)?;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
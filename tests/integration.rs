@@ -0,0 +1,492 @@
+extern crate notify;
+extern crate tempfile;
+extern crate unison_fsmonitor;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event as FsEvent, EventKind};
+use unison_fsmonitor::{FakeEventSource, Monitor};
+
+fn write_event(path: PathBuf) -> FsEvent {
+    FsEvent::new(EventKind::Modify(ModifyKind::Any)).add_path(path)
+}
+
+fn create_event(path: PathBuf) -> FsEvent {
+    FsEvent::new(EventKind::Create(CreateKind::File)).add_path(path)
+}
+
+// Mirrors the shape FSEvents (macOS) and kqueue (BSD) report for each side of
+// a rename: a single path with no label for which side it is.
+fn rename_any_event(path: PathBuf) -> FsEvent {
+    FsEvent::new(EventKind::Modify(ModifyKind::Name(RenameMode::Any))).add_path(path)
+}
+
+// Changes under a LINK target must be reported under each link path that
+// points at it, not just the canonical target path.
+#[test]
+fn link_map_translates_changes_to_every_link_path() {
+    let replica = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let link = replica.path().join("linked");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target.path(), &link).unwrap();
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir(target.path(), &link).unwrap();
+
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("LINK linked", &mut source, &mut out)
+        .unwrap();
+
+    let changed = fs::canonicalize(target.path()).unwrap().join("file.txt");
+    monitor
+        .handle_fs_event(write_event(changed), &mut source)
+        .unwrap();
+
+    let expected: HashSet<PathBuf> = vec![PathBuf::from("linked").join("file.txt")]
+        .into_iter()
+        .collect();
+    assert_eq!(monitor.pending_changes("default"), expected);
+}
+
+// On FSEvents/kqueue each side of a rename arrives as its own
+// `RenameMode::Any` event carrying a single path, with nothing in the event
+// to say which side it is. Both the old path and the new path must end up
+// pending for the replica. (The Remove/Create kind each side gets is not
+// visible through `pending_changes`; the vanished-LINK-target test below
+// exercises the Remove side.)
+#[test]
+fn rename_any_events_mark_both_the_old_and_new_paths_dirty() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+
+    let old_path = replica.path().join("old.txt");
+    let new_path = replica.path().join("new.txt");
+    fs::write(&old_path, b"hi").unwrap();
+    fs::rename(&old_path, &new_path).unwrap();
+
+    monitor
+        .handle_fs_event(rename_any_event(old_path), &mut source)
+        .unwrap();
+    monitor
+        .handle_fs_event(rename_any_event(new_path), &mut source)
+        .unwrap();
+
+    let changes = monitor.pending_changes("default");
+    assert!(changes.contains(&PathBuf::from("old.txt")));
+    assert!(changes.contains(&PathBuf::from("new.txt")));
+}
+
+// A LINK target renamed away must be unwatched even when the platform only
+// reports it as a single-path `RenameMode::Any` event rather than a typed
+// Remove -- otherwise the watch on a vanished subtree leaks.
+#[test]
+fn rename_any_event_for_a_vanished_link_target_unwatches_it() {
+    let replica = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let link = replica.path().join("linked");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target.path(), &link).unwrap();
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir(target.path(), &link).unwrap();
+
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("LINK linked", &mut source, &mut out)
+        .unwrap();
+
+    let canonical_target = fs::canonicalize(target.path()).unwrap();
+    assert!(source.watched.contains(&canonical_target));
+
+    fs::remove_dir(&target).unwrap();
+    monitor
+        .handle_fs_event(rename_any_event(canonical_target.clone()), &mut source)
+        .unwrap();
+
+    assert!(
+        !source.watched.contains(&canonical_target),
+        "a vanished LINK target should be unwatched"
+    );
+}
+
+// CHANGES must return exactly the accumulated changes for a replica and
+// clear them, so a second CHANGES for the same replica reports nothing new.
+#[test]
+fn changes_drains_pending_changes_for_the_replica() {
+    let replica = tempfile::tempdir().unwrap();
+
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+
+    let changed = replica.path().join("a.txt");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+    assert_eq!(monitor.pending_changes("default").len(), 1);
+
+    out = Cursor::new(vec![]);
+    monitor
+        .handle_input("CHANGES default", &mut source, &mut out)
+        .unwrap();
+
+    let reply = String::from_utf8(out.into_inner()).unwrap();
+    assert!(reply.contains("RECURSIVE"));
+    assert!(reply.contains("a.txt"));
+    assert!(monitor.pending_changes("default").is_empty());
+}
+
+// Many changes spread across unrelated top-level directories, each well
+// under the coalesce threshold and not numerous enough in total to cross it
+// either, must be reported per-directory rather than collapsed into a single
+// RECURSIVE for the whole replica root.
+#[test]
+fn coalesce_does_not_collapse_unrelated_top_level_directories() {
+    let replica = tempfile::tempdir().unwrap();
+
+    let mut monitor = Monitor::new(Duration::from_millis(250), 25, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    out = Cursor::new(vec![]);
+
+    for i in 0..20 {
+        let changed = replica.path().join(format!("dir{}", i)).join("file.txt");
+        monitor
+            .handle_fs_event(create_event(changed), &mut source)
+            .unwrap();
+    }
+
+    monitor
+        .handle_input("CHANGES default", &mut source, &mut out)
+        .unwrap();
+
+    let reply = String::from_utf8(out.into_inner()).unwrap();
+    let recursive_lines = reply.lines().filter(|l| l.starts_with("RECURSIVE")).count();
+    assert_eq!(
+        recursive_lines, 20,
+        "unrelated top-level directories should not collapse to the replica root:\n{}",
+        reply
+    );
+}
+
+// A burst spread across more leaf directories than the coalesce threshold,
+// each touched by only a single file, never crosses the per-directory
+// threshold on its own -- but the replica as a whole is genuinely dirty, so
+// it must still fold down to one RECURSIVE for the replica root rather than
+// emitting one line per directory.
+#[test]
+fn coalesce_collapses_to_the_replica_root_when_the_whole_tree_is_dirty() {
+    let replica = tempfile::tempdir().unwrap();
+
+    let mut monitor = Monitor::new(Duration::from_millis(250), 10, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    out = Cursor::new(vec![]);
+
+    for i in 0..20 {
+        let changed = replica.path().join(format!("dir{}", i)).join("file.txt");
+        monitor
+            .handle_fs_event(create_event(changed), &mut source)
+            .unwrap();
+    }
+
+    monitor
+        .handle_input("CHANGES default", &mut source, &mut out)
+        .unwrap();
+
+    let reply = String::from_utf8(out.into_inner()).unwrap();
+    assert!(
+        reply.contains("RECURSIVE ."),
+        "a whole-tree-dirty burst spread across many small subtrees should fold to the replica root:\n{}",
+        reply
+    );
+}
+
+// A CHANGES notification must not be sent out until the debounce window has
+// elapsed with no further FS activity, so a burst of events only produces
+// one notification.
+#[test]
+fn flush_is_delayed_until_the_debounce_window_is_quiet() {
+    let replica = tempfile::tempdir().unwrap();
+
+    let debounce = Duration::from_millis(60);
+    let mut monitor = Monitor::new(debounce, 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    out = Cursor::new(vec![]);
+
+    let changed = replica.path().join("a.txt");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+
+    assert!(!monitor.flush_if_quiet(&mut out).unwrap());
+    assert!(out.get_ref().is_empty());
+
+    std::thread::sleep(debounce * 2);
+
+    assert!(monitor.flush_if_quiet(&mut out).unwrap());
+    let reply = String::from_utf8(out.into_inner()).unwrap();
+    assert!(reply.contains("CHANGES default"));
+}
+
+// STARTing a replica whose canonical path was already seen before (the
+// monitor restarted since) must force a full rescan so changes made while it
+// was down aren't silently missed, and that rescan must be reported with a
+// real path token (`RECURSIVE .`), not an empty one Unison's whitespace-split
+// parser would drop.
+#[test]
+fn restart_rescan_uses_a_root_sentinel_not_an_empty_path() {
+    let replica = tempfile::tempdir().unwrap();
+    let state_dir = tempfile::tempdir().unwrap();
+    let state_path = state_dir.path().join("event-state");
+
+    let mut source = FakeEventSource::new();
+    let mut first = Monitor::new(Duration::from_millis(250), 128, Some(state_path.clone())).unwrap();
+    let mut out = Cursor::new(vec![]);
+    first
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+
+    // A fresh `Monitor` sharing the same state file stands in for the
+    // process having restarted.
+    let mut second = Monitor::new(Duration::from_millis(250), 128, Some(state_path)).unwrap();
+    let mut out = Cursor::new(vec![]);
+    second
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+
+    out = Cursor::new(vec![]);
+    second
+        .handle_input("CHANGES default", &mut source, &mut out)
+        .unwrap();
+
+    let reply = String::from_utf8(out.into_inner()).unwrap();
+    assert!(
+        reply.contains("RECURSIVE ."),
+        "restart rescan should report a root sentinel, not a bare RECURSIVE:\n{}",
+        reply
+    );
+}
+
+// RESET must forget that a replica was ever seen, so re-STARTing the same
+// path later in the same session is treated as first contact instead of
+// triggering a spurious full rescan.
+#[test]
+fn reset_forgets_the_replica_so_a_later_start_does_not_force_a_rescan() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("RESET default", &mut source, &mut out)
+        .unwrap();
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+
+    assert!(monitor.pending_changes("default").is_empty());
+}
+
+// IGNORE patterns must have gitignore's directory semantics: a bare name
+// with no `/` ignores the directory itself and everything below it, not
+// just a path that matches the pattern literally.
+#[test]
+fn ignore_pattern_excludes_the_whole_directory_it_names() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("IGNORE default node_modules", &mut source, &mut out)
+        .unwrap();
+
+    let changed = replica.path().join("node_modules").join("foo.js");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+
+    assert!(monitor.pending_changes("default").is_empty());
+}
+
+// gitignore's directory-only trailing-slash syntax (`node_modules/`) should
+// exclude the directory's contents the same as the bare name does.
+#[test]
+fn ignore_pattern_with_a_trailing_slash_excludes_the_whole_directory_it_names() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("IGNORE default node_modules/", &mut source, &mut out)
+        .unwrap();
+
+    let changed = replica.path().join("node_modules").join("foo.js");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+
+    assert!(monitor.pending_changes("default").is_empty());
+}
+
+// A bare-name IGNORE pattern must also suppress the named entry itself, not
+// just its contents -- a file directly matching the pattern at the replica
+// root counts as ignored too.
+#[test]
+fn ignore_pattern_excludes_a_top_level_match_of_the_name_itself() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("IGNORE default ignored.txt", &mut source, &mut out)
+        .unwrap();
+
+    let changed = replica.path().join("ignored.txt");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+
+    assert!(monitor.pending_changes("default").is_empty());
+}
+
+// IGNORE must support plain gitignore-style file extension patterns, not
+// just directory names: `*.log` should suppress a change to `a.log`
+// anywhere under the replica.
+#[test]
+fn ignore_pattern_matches_a_file_extension_glob() {
+    let replica = tempfile::tempdir().unwrap();
+    let mut monitor = Monitor::new(Duration::from_millis(250), 128, None).unwrap();
+    let mut source = FakeEventSource::new();
+    let mut out = Cursor::new(vec![]);
+
+    monitor
+        .handle_input(
+            &format!("START default {}", replica.path().display()),
+            &mut source,
+            &mut out,
+        )
+        .unwrap();
+    monitor
+        .handle_input("IGNORE default *.log", &mut source, &mut out)
+        .unwrap();
+
+    let changed = replica.path().join("a.log");
+    monitor
+        .handle_fs_event(create_event(changed), &mut source)
+        .unwrap();
+
+    assert!(monitor.pending_changes("default").is_empty());
+}